@@ -8,26 +8,51 @@ import (
 	"regexp"
 	"strconv"
 	"strings"
+	"time"
 
 	"github.com/sirupsen/logrus"
 )
 
 const (
-	MTU           = "1500"
-	MARK          = 87
-	ADDR_INTF_STR = `(?:[0-9]{1,3}\.){3}[0-9]{1,3}(?:\/\d\d) dev (?P<iface>[a-zA-Z0-9_]+).*src (?P<addr>(?:[0-9]{1,3}\.){3}[0-9]{1,3}) .*`
+	MTU                  = "1500"
+	MARK                 = 87
+	ADDR_INTF_STR        = `(?:[0-9]{1,3}\.){3}[0-9]{1,3}(?:\/\d\d) dev (?P<iface>[a-zA-Z0-9_]+).*src (?P<addr>(?:[0-9]{1,3}\.){3}[0-9]{1,3}) .*`
+	XTABLES_LOCK_ERROR   = "xtables lock"
+	DEF_COMMAND_RETRIES  = "3"
+	COMMAND_RETRY_PERIOD = time.Millisecond * 500
 )
 
-var ADDR_INTF_REGEXP = regexp.MustCompile(ADDR_INTF_STR)
+var (
+	ADDR_INTF_REGEXP = regexp.MustCompile(ADDR_INTF_STR)
+	COMMAND_RETRIES  int
+)
+
+func init() {
+	retries, err := strconv.Atoi(getEnv("COMMAND_RETRIES", DEF_COMMAND_RETRIES))
+	if err != nil || retries < 0 {
+		logrus.Fatalln("Couldn't parse command retries environmental variable!")
+	}
+	COMMAND_RETRIES = retries
+}
 
 func runCommand(cmd string, args ...string) string {
-	command := exec.Command(cmd, args...)
-	output, err := command.CombinedOutput()
-	if err != nil {
+	for attempt := 1; ; attempt++ {
+		command := exec.Command(cmd, args...)
+		output, err := command.CombinedOutput()
+		if err == nil {
+			return string(output)
+		}
+
+		// Retry if xtables lock is held by another process, fail on any other error
+		if attempt <= COMMAND_RETRIES && strings.Contains(string(output), XTABLES_LOCK_ERROR) {
+			logrus.Warnf("Command %s couldn't acquire xtables lock (attempt %d of %d)", cmd, attempt, COMMAND_RETRIES+1)
+			time.Sleep(COMMAND_RETRY_PERIOD * time.Duration(attempt))
+			continue
+		}
+
 		logrus.Errorf("Command %s output: %s", cmd, output)
 		logrus.Fatalln("Running command error:", err)
 	}
-	return string(output)
 }
 
 func AllocateInterface(name string, tunnelIP *net.IP, tunnelNetwork *net.IPNet) {
@@ -59,6 +59,7 @@ func ListenControlPort(ip string, port int) {
 		read, err := connection.Read(buffer)
 		if err != nil || read == 0 {
 			logrus.Errorf("Reading control error (%d bytes read): %v", read, err)
+			connection.Close()
 			continue
 		}
 
@@ -66,6 +67,7 @@ func ListenControlPort(ip string, port int) {
 		address, err := net.ResolveTCPAddr(TCP, connection.RemoteAddr().String())
 		if err != nil || read == 0 {
 			logrus.Errorf("Resolving remote user address error: %v", connection.RemoteAddr().String())
+			connection.Close()
 			continue
 		}
 
@@ -77,6 +79,7 @@ func ListenControlPort(ip string, port int) {
 		if err != nil {
 			logrus.Warnln("Couldn't parse message from user", userID)
 			SendStatusToUser(ERROR, nil, connection)
+			connection.Close()
 			continue
 		}
 
@@ -3,6 +3,7 @@ package main
 import (
 	"encoding/binary"
 	"errors"
+	"fmt"
 	"math"
 	"math/rand"
 )
@@ -42,7 +43,7 @@ func EncodeMessage(status Status, data []byte) ([]byte, error) {
 
 	random_length := rand.Int() % Min(available_space-length, math.MaxUint16)
 	total_length := random_length + length + GRAVITY + HEADER
-	prefix_length := rand.Int() % Min(math.MaxUint8, total_length)
+	prefix_length := rand.Int() % (Min(math.MaxUint8-GRAVITY, random_length) + 1)
 
 	payload := make([]byte, total_length)
 	_, err := rand.Read(payload)
@@ -62,13 +63,26 @@ func EncodeMessage(status Status, data []byte) ([]byte, error) {
 }
 
 func DecodeMessage(data []byte) (Status, []byte, error) {
-	offset := uint16(data[GRAVITY-1])
+	if len(data) < GRAVITY {
+		return UNDEF, nil, fmt.Errorf("message length (%d) is less than gravity (%d)", len(data), GRAVITY)
+	}
+
+	offset := int(data[GRAVITY-1])
+	if offset < GRAVITY {
+		return UNDEF, nil, fmt.Errorf("message data pointer (%d) points inside gravity (%d)", offset, GRAVITY)
+	} else if len(data) < offset+3 {
+		return UNDEF, nil, fmt.Errorf("message length (%d) is less than header end (%d)", len(data), offset+3)
+	}
+
 	status := convertToStatus(data[offset])
-	length := binary.BigEndian.Uint16(data[offset+1 : offset+3])
+	length := int(binary.BigEndian.Uint16(data[offset+1 : offset+3]))
 	if length == 0 {
 		return status, nil, nil
-	} else {
-		start := offset + 3
-		return status, data[start : start+length], nil
 	}
+
+	start := offset + 3
+	if len(data) < start+length {
+		return UNDEF, nil, fmt.Errorf("message length (%d) is less than data end (%d)", len(data), start+length)
+	}
+	return status, data[start : start+length], nil
 }
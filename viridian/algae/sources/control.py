@@ -1,18 +1,123 @@
 from ipaddress import IPv4Address
 from multiprocessing import Process
-from socket import AF_INET, SHUT_WR, SOCK_STREAM, socket
+from multiprocessing.connection import wait
+from os import environ
+from random import randint
+from socket import AF_INET, SHUT_WR, SOCK_DGRAM, SOCK_STREAM, socket, timeout
+from struct import pack
+from subprocess import run
+from time import monotonic, sleep
+from typing import NoReturn, Optional, Tuple
 
-from .crypto import _MESSAGE_MAX_LEN, Status, decode_message, decrypt_rsa, encode_message, get_public_key, initialize_symmetric
-from .outputs import logger
+from .crypto import _MESSAGE_MAX_LEN, MalformedMessage, Status, TruncatedMessage, decode_message, decrypt_rsa, encode_message, get_public_key, initialize_symmetric
+from .outputs import get_logger
 from .tunnel import Tunnel
 
+logger = get_logger("control")
+
+_PROBE_PORT = 53
+_PROBE_TIMEOUT = 2.0
+
+_DNS_HEADER_LEN = 12
+_DNS_MAX_LEN = 512
+_DNS_RECURSION_DESIRED = 0x0100
+_DNS_TYPE_A = 1
+_DNS_CLASS_IN = 1
+
+
+class TunnelNotCarryingTraffic(RuntimeError):
+    pass
+
+
+class ConnectionClosed(RuntimeError):
+    pass
+
+
+class ControlTimeout(RuntimeError):
+    pass
+
+
+def _reset_timeout(connection: socket, deadline: Optional[float]) -> None:
+    if deadline is not None:
+        remaining = deadline - monotonic()
+        if remaining <= 0:
+            raise timeout("Control deadline expired!")
+        connection.settimeout(remaining)
+
+
+def _receive_message(connection: socket, deadline: Optional[float] = None) -> bytes:
+    message = bytes()
+    while len(message) < _MESSAGE_MAX_LEN:
+        _reset_timeout(connection, deadline)
+        chunk = connection.recv(_MESSAGE_MAX_LEN - len(message))
+        if len(chunk) == 0:
+            break
+        message += chunk
+    if len(message) == 0:
+        raise ConnectionClosed("Caerulean closed control connection without sending a message!")
+    return message
+
+
+def _dns_query(identifier: int, host: str) -> bytes:
+    header = pack("!HHHHHH", identifier, _DNS_RECURSION_DESIRED, 1, 0, 0, 0)
+    question = b"".join(len(label).to_bytes(1, "big") + label.encode("ascii") for label in host.split("."))
+    return header + question + b"\x00" + pack("!HH", _DNS_TYPE_A, _DNS_CLASS_IN)
+
+
+def _query_resolver(resolver: str, host: str) -> None:
+    identifier = randint(0, 0xFFFF)
+    with socket(AF_INET, SOCK_DGRAM) as gate:
+        gate.settimeout(_PROBE_TIMEOUT)
+        gate.connect((resolver, _PROBE_PORT))
+        gate.send(_dns_query(identifier, host))
+        reply = gate.recv(_DNS_MAX_LEN)
+    if len(reply) < _DNS_HEADER_LEN or int.from_bytes(reply[:2], "big") != identifier:
+        raise ValueError(f"Unexpected reply from DNS resolver {resolver}!")
+
 
 class Controller:
-    def __init__(self, name: str, encode: bool, mtu: int, buff: int, addr: IPv4Address, sea_port: int, ctrl_port: int):
+    def __init__(
+        self,
+        name: str,
+        encode: bool,
+        mtu: int,
+        buff: int,
+        addr: IPv4Address,
+        sea_port: int,
+        ctrl_port: int,
+        ctrl_timeout: float,
+        local: Optional[IPv4Address],
+        rotate: int,
+        probe: int,
+        backoff: float,
+        probe_resolver: IPv4Address,
+        probe_host: str,
+        gateway: Optional[IPv4Address],
+        dscp: int,
+        mark: int,
+        fallback: bool,
+        max_drops: int,
+        routing: bool,
+        suppress: bool,
+        up_hook: Optional[str],
+        down_hook: Optional[str],
+        up_hook_required: bool,
+    ):
         self._encode = encode
+        self._probe_attempts = probe
+        self._probe_backoff = backoff
+        self._probe_resolver = str(probe_resolver)
+        self._probe_host = probe_host
         self._address = str(addr)
         self._ctrl_port = ctrl_port
-        self._interface = Tunnel(name, encode, mtu, buff, addr, sea_port)
+        self._ctrl_timeout = ctrl_timeout
+        self._local = local
+        self._suppress_termin = suppress
+        self._up_hook = up_hook
+        self._down_hook = down_hook
+        self._up_hook_required = up_hook_required
+        self._down_hook_pending = False
+        self._interface = Tunnel(name, encode, mtu, buff, addr, sea_port, rotate, gateway, dscp, mark, fallback, max_drops, routing, local)
 
         self._receiver_process: Process
         self._sender_process: Process
@@ -23,44 +128,84 @@ class Controller:
             self._initialize_control()
             logger.info("Starting tunnel worker processes...")
             self._turn_tunnel_on()
+            if self._probe_attempts > 0:
+                logger.info("Probing tunnel connectivity...")
+                self._probe_connection()
+            if self._up_hook is not None:
+                self._run_up_hook(self._up_hook)
             logger.info("Starting controller process...")
             self._perform_control()
         except SystemExit:
             self._clean_tunnel()
 
-    def _initialize_control(self) -> None:
+    def _exchange_control(self, status: Status, data: Optional[bytes] = None) -> Tuple[Status, Optional[bytes]]:
         caerulean_address = (self._address, self._ctrl_port)
+        deadline = monotonic() + self._ctrl_timeout
 
         with socket(AF_INET, SOCK_STREAM) as gate:
-            gate.connect(caerulean_address)
-            logger.debug(f"Sending control to caerulean {self._address}:{self._ctrl_port}")
-
-            if not self._encode:
-                request = encode_message(Status.SUCCESS)
-                gate.sendall(request)
+            if self._local is not None:
+                gate.bind((str(self._local), 0))
+            try:
+                _reset_timeout(gate, deadline)
+                gate.connect(caerulean_address)
+                logger.debug(f"Sending control to caerulean {self._address}:{self._ctrl_port}")
+                _reset_timeout(gate, deadline)
+                gate.sendall(encode_message(status, data))
                 gate.shutdown(SHUT_WR)
+                return decode_message(_receive_message(gate, deadline))
+            except timeout:
+                raise ControlTimeout(f"Caerulean {self._address}:{self._ctrl_port} didn't respond in {self._ctrl_timeout} seconds!")
 
-                packet = gate.recv(_MESSAGE_MAX_LEN)
-                status, _ = decode_message(packet)
+    def _initialize_control(self) -> None:
+        if not self._encode:
+            status, _ = self._exchange_control(Status.SUCCESS)
 
-                if status == Status.SUCCESS:
-                    logger.info(f"Connected to caerulean {self._address}:{self._ctrl_port} as Proxy successfully!")
-                else:
-                    logger.info(f"Error connecting to caerulean (status: {status})!")
+            if status == Status.SUCCESS:
+                logger.info(f"Connected to caerulean {self._address}:{self._ctrl_port} as Proxy successfully!")
+            else:
+                logger.info(f"Error connecting to caerulean (status: {status})!")
 
+        else:
+            status, key = self._exchange_control(Status.PUBLIC, get_public_key())
+
+            if status == Status.SUCCESS and key is not None:
+                initialize_symmetric(decrypt_rsa(key))
+                logger.info(f"Connected to caerulean {self._address}:{self._ctrl_port} as VPN successfully!")
             else:
-                public_key = encode_message(Status.PUBLIC, get_public_key())
-                gate.sendall(public_key)
-                gate.shutdown(SHUT_WR)
+                raise RuntimeError(f"Couldn't exchange keys with caerulean (status: {status})!")
 
-                packet = gate.recv(_MESSAGE_MAX_LEN)
-                status, key = decode_message(packet)
+    def _probe_connection(self) -> None:
+        for attempt in range(self._probe_attempts):
+            try:
+                _query_resolver(self._probe_resolver, self._probe_host)
+                logger.info(f"Tunnel is carrying traffic (DNS resolver {self._probe_resolver} answered)")
+                return
+            except (OSError, ValueError) as e:
+                logger.warning(f"Connectivity probe {attempt + 1}/{self._probe_attempts} failed: {e}")
+            if attempt + 1 < self._probe_attempts:
+                sleep(self._probe_backoff * 2 ** attempt)
 
-                if status == Status.SUCCESS and key is not None:
-                    initialize_symmetric(decrypt_rsa(key))
-                    logger.info(f"Connected to caerulean {self._address}:{self._ctrl_port} as VPN successfully!")
-                else:
-                    raise RuntimeError(f"Couldn't exchange keys with caerulean (status: {status})!")
+        self._abort_control(TunnelNotCarryingTraffic(f"Connected to caerulean, but DNS resolver {self._probe_resolver} couldn't be reached through the tunnel!"))
+
+    def _run_hook(self, command: str) -> int:
+        hook_env = dict(SEASIDE_TUNNEL=self._interface.name, SEASIDE_ADDRESS=self._interface.default_ip, SEASIDE_CAERULEAN=self._address)
+        logger.info(f"Running hook command: {command}")
+        return run(command, shell=True, env={**environ, **hook_env}).returncode
+
+    def _run_up_hook(self, command: str) -> None:
+        code = self._run_hook(command)
+        if code != 0 and self._up_hook_required:
+            self._down_hook_pending = False
+            self._abort_control(RuntimeError(f"Up hook command failed (exit code: {code})!"))
+        elif code != 0:
+            logger.warning(f"Up hook command failed (exit code: {code})!")
+
+    def _run_down_hook(self) -> None:
+        if self._down_hook is not None and self._down_hook_pending and self._interface.operational:
+            self._down_hook_pending = False
+            code = self._run_hook(self._down_hook)
+            if code != 0:
+                logger.warning(f"Down hook command failed (exit code: {code})!")
 
     def _turn_tunnel_on(self) -> None:
         self._interface.up()
@@ -68,6 +213,7 @@ class Controller:
         self._sender_process = Process(target=self._interface.send_to_caerulean, name="sender", daemon=True)
         self._receiver_process.start()
         self._sender_process.start()
+        self._down_hook_pending = True
 
     def _turn_tunnel_off(self) -> None:
         self._receiver_process.terminate()
@@ -76,20 +222,37 @@ class Controller:
 
     def _clean_tunnel(self) -> None:
         if self._interface.operational:
+            self._run_down_hook()
             logger.warning("Terminating whirlpool connection...")
             self._turn_tunnel_off()
             logger.warning("Gracefully stopping algae client...")
             self._interface.delete()
 
+    def _supervise_workers(self) -> None:
+        for worker in (self._receiver_process, self._sender_process):
+            if not worker.is_alive():
+                logger.error(f"Tunnel worker process {worker.name} exited unexpectedly (exit code: {worker.exitcode})!")
+                self._abort_control(RuntimeError(f"Tunnel worker process {worker.name} is no longer running!"))
+
     def _perform_control(self) -> None:
         with socket(AF_INET, SOCK_STREAM) as gate:
             gate.bind((self._interface.default_ip, self._ctrl_port))
             gate.listen(1)
 
             while self._interface.operational:
+                ready = wait([gate, self._receiver_process.sentinel, self._sender_process.sentinel])
+                if gate not in ready:
+                    self._supervise_workers()
+                    continue
+
                 connection, _ = gate.accept()
-                packet = connection.recv(_MESSAGE_MAX_LEN)
-                status, _ = decode_message(packet)
+                try:
+                    status, _ = decode_message(_receive_message(connection, monotonic() + self._ctrl_timeout))
+                except (ConnectionClosed, TruncatedMessage, MalformedMessage, timeout) as e:
+                    logger.warning(f"Couldn't receive control message: {e}")
+                    continue
+                finally:
+                    connection.close()
 
                 if status == Status.NO_PASS:
                     logger.info("Server lost session key, re-initializing control!")
@@ -112,21 +275,27 @@ class Controller:
                     self._clean_tunnel()
                     raise SystemExit("Requested caerulean is no longer available!")
 
-    def break_control(self) -> None:
-        caerulean_address = (self._address, self._ctrl_port)
+    def _abort_control(self, error: RuntimeError) -> NoReturn:
+        try:
+            self.break_control()
+        except (RuntimeError, OSError, ValueError) as e:
+            logger.warning(f"Couldn't disconnect from caerulean: {e}")
+        raise error
 
-        with socket(AF_INET, SOCK_STREAM) as gate:
-            gate.connect(caerulean_address)
-            request = encode_message(Status.TERMIN)
-            gate.sendall(request)
-            gate.shutdown(SHUT_WR)
+    def break_control(self) -> None:
+        self._run_down_hook()
+        if self._suppress_termin:
+            logger.info(f"Leaving caerulean {self._address}:{self._ctrl_port} without disconnection request")
+            self._clean_tunnel()
+            return
 
-            packet = gate.recv(_MESSAGE_MAX_LEN)
-            status, _ = decode_message(packet)
+        try:
+            status, _ = self._exchange_control(Status.TERMIN)
 
             if status == Status.SUCCESS:
                 logger.info(f"Disconnected from caerulean {self._address}:{self._ctrl_port} successfully!")
             else:
                 logger.info(f"Error disconnecting from caerulean (status: {status})!")
 
-        self._clean_tunnel()
+        finally:
+            self._clean_tunnel()
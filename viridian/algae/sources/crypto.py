@@ -23,6 +23,14 @@ _SIZE_UINT_8 = 255
 _SIZE_UINT_16 = 65535
 
 
+class TruncatedMessage(ValueError):
+    pass
+
+
+class MalformedMessage(ValueError):
+    pass
+
+
 class Status(IntEnum):
     UNDEF = 0
     SUCCESS = 1
@@ -77,7 +85,7 @@ def encode_message(status: Status, data: Optional[bytes] = None) -> bytes:
         raise RuntimeError(f"Length of data ({length}) is greater than max message length ({available_space})!")
 
     random_length = randint(0, min(available_space - length, _SIZE_UINT_16))
-    prefix_length = randint(0, min(_SIZE_UINT_8, random_length))
+    prefix_length = randint(0, min(_SIZE_UINT_8 - _MESSAGE_GRAVITY, random_length))
 
     pointer = (prefix_length + _MESSAGE_GRAVITY).to_bytes(1, "big")
     prefix = get_random_bytes(_MESSAGE_GRAVITY - 1) + pointer + get_random_bytes(prefix_length)
@@ -87,7 +95,13 @@ def encode_message(status: Status, data: Optional[bytes] = None) -> bytes:
 
 
 def decode_message(data: bytes) -> Tuple[Status, Optional[bytes]]:
+    if len(data) < _MESSAGE_GRAVITY:
+        raise TruncatedMessage(f"Message length ({len(data)}) is less than message gravity ({_MESSAGE_GRAVITY})!")
     offset = data[_MESSAGE_GRAVITY - 1]
+    if offset < _MESSAGE_GRAVITY:
+        raise MalformedMessage(f"Message data pointer ({offset}) points inside message gravity ({_MESSAGE_GRAVITY})!")
+    elif len(data) < offset + _MESSAGE_HEADER_LEN:
+        raise TruncatedMessage(f"Message length ({len(data)}) is less than message header end ({offset + _MESSAGE_HEADER_LEN})!")
     status = Status(data[offset])
 
     start, end = offset + 1, offset + 3
@@ -96,4 +110,6 @@ def decode_message(data: bytes) -> Tuple[Status, Optional[bytes]]:
         return status, None
     else:
         start, end = offset + 3, offset + 3 + length
+        if len(data) < end:
+            raise TruncatedMessage(f"Message length ({len(data)}) is less than message data end ({end})!")
         return status, data[start:end]
@@ -3,12 +3,15 @@ from ipaddress import IPv4Address
 from multiprocessing import current_process
 from signal import SIGINT, SIGTERM, signal
 from sys import argv, exit
-from typing import Sequence
+from typing import Optional, Sequence
 
 from colorama import just_fix_windows_console
+from pyroute2.netns import setns
 
 from .control import Controller
-from .outputs import logger
+from .outputs import adjust_level, get_logger
+
+logger = get_logger("main")
 
 _DEFAULT_NAME = "seatun"
 _DEFAULT_VPN = True
@@ -17,6 +20,17 @@ _DEFAULT_BUFFER = 2000
 _DEFAULT_ADDRESS = "127.0.0.1"
 _DEFAULT_SEA_PORT = 8542
 _DEFAULT_CONTROL_PORT = 8543
+_DEFAULT_CTRL_TIMEOUT = 10.0
+_DEFAULT_ROTATE = 0
+_DEFAULT_PROBE = 0
+_DEFAULT_BACKOFF = 1.0
+_DEFAULT_PROBE_RESOLVER = "8.8.8.8"
+_DEFAULT_PROBE_HOST = "example.com"
+_DEFAULT_DSCP = 0
+_MAX_DSCP = 63
+_DEFAULT_MARK = 0
+_MAX_MARK = 0xFFFFFFFF
+_DEFAULT_MAX_DROPS = 16
 
 
 def boolean(value: str) -> bool:
@@ -28,6 +42,46 @@ def boolean(value: str) -> bool:
         raise ArgumentTypeError(f"Unknown boolean value: {value}")
 
 
+def dscp(value: str) -> int:
+    number = int(value)
+    if 0 <= number <= _MAX_DSCP:
+        return number
+    else:
+        raise ArgumentTypeError(f"DSCP value should be in range 0-{_MAX_DSCP}: {value}")
+
+
+def positive(value: str) -> int:
+    number = int(value)
+    if number > 0:
+        return number
+    else:
+        raise ArgumentTypeError(f"Value should be positive: {value}")
+
+
+def non_negative(value: str) -> int:
+    number = int(value)
+    if number >= 0:
+        return number
+    else:
+        raise ArgumentTypeError(f"Value should not be negative: {value}")
+
+
+def duration(value: str) -> float:
+    number = float(value)
+    if number > 0:
+        return number
+    else:
+        raise ArgumentTypeError(f"Duration should be positive: {value}")
+
+
+def mark(value: str) -> int:
+    number = int(value)
+    if 0 <= number <= _MAX_MARK:
+        return number
+    else:
+        raise ArgumentTypeError(f"Socket mark should be in range 0-{_MAX_MARK}: {value}")
+
+
 parser = ArgumentParser()
 parser.add_argument("-t", "--tunnel", dest="name", default=_DEFAULT_NAME, help=f"Tunnel interface name (default: {_DEFAULT_NAME})")
 parser.add_argument("-e", "--vpn", dest="encode", default=_DEFAULT_VPN, type=boolean, help=f"Use as VPN (encode traffic) (default: {_DEFAULT_VPN})")
@@ -36,6 +90,26 @@ parser.add_argument("-b", "--buffer", dest="buff", default=_DEFAULT_BUFFER, type
 parser.add_argument("-a", "--address", dest="addr", default=_DEFAULT_ADDRESS, type=IPv4Address, help=f"Caerulean remote IP address (default: {_DEFAULT_ADDRESS})")
 parser.add_argument("-p", "--sea-port", dest="sea_port", default=_DEFAULT_SEA_PORT, type=int, help=f"Caerulean remote port number (default: {_DEFAULT_SEA_PORT})")
 parser.add_argument("-c", "--ctrl-port", dest="ctrl_port", default=_DEFAULT_CONTROL_PORT, type=int, help=f"Caerulean remote control port number (default: {_DEFAULT_CONTROL_PORT})")
+parser.add_argument("-o", "--ctrl-timeout", dest="ctrl_timeout", default=_DEFAULT_CTRL_TIMEOUT, type=duration, help=f"Control timeout, seconds (default: {_DEFAULT_CTRL_TIMEOUT})")
+parser.add_argument("-l", "--local-address", dest="local", default=None, type=IPv4Address, help="Local address for tunnel and connections (default: route to caerulean)")
+parser.add_argument("-g", "--tunnel-gateway", dest="gateway", default=None, type=IPv4Address, help="Next hop for tunnel default route (default: tunnel address)")
+parser.add_argument("-d", "--dscp", dest="dscp", default=_DEFAULT_DSCP, type=dscp, help=f"DSCP value for outgoing seaside packets (default: {_DEFAULT_DSCP})")
+parser.add_argument("-k", "--socket-mark", dest="mark", default=_DEFAULT_MARK, type=mark, help=f"Outgoing seaside packets firewall mark (default: {_DEFAULT_MARK})")
+parser.add_argument("-z", "--no-routing", dest="routing", action="store_false", help="Do not set tunnel as default route")
+parser.add_argument("-f", "--fallback-direct", dest="fallback", action="store_true", help="Keep direct default route as a fallback, reduces leak protection")
+parser.add_argument("-s", "--suppress-termin", dest="suppress", action="store_true", help="Do not send disconnection request on exit")
+parser.add_argument("-x", "--max-drops", dest="max_drops", default=_DEFAULT_MAX_DROPS, type=positive, help=f"Max undecryptable packets in a row (default: {_DEFAULT_MAX_DROPS})")
+parser.add_argument("--up-hook", dest="up_hook", default=None, help="Shell command to run after connection (default: none)")
+parser.add_argument("--down-hook", dest="down_hook", default=None, help="Shell command to run before disconnection (default: none)")
+parser.add_argument("--up-hook-required", dest="up_hook_required", action="store_true", help="Disconnect if up hook command fails")
+parser.add_argument("--probe-attempts", dest="probe", default=_DEFAULT_PROBE, type=non_negative, help=f"DNS probe attempts, 0 to disable (default: {_DEFAULT_PROBE})")
+parser.add_argument("--probe-backoff", dest="backoff", default=_DEFAULT_BACKOFF, type=float, help=f"Initial delay between probe attempts, seconds (default: {_DEFAULT_BACKOFF})")
+parser.add_argument("--probe-resolver", dest="probe_resolver", default=_DEFAULT_PROBE_RESOLVER, type=IPv4Address, help=f"Probe DNS resolver (default: {_DEFAULT_PROBE_RESOLVER})")
+parser.add_argument("--probe-host", dest="probe_host", default=_DEFAULT_PROBE_HOST, help=f"Probe host name (default: {_DEFAULT_PROBE_HOST})")
+parser.add_argument("-n", "--netns", dest="netns", default=None, help="Network namespace to run the client in (default: current namespace)")
+parser.add_argument("-q", "--quiet", dest="quiet", action="store_true", help="Only output errors (overrides LOG_LEVEL)")
+parser.add_argument("-v", "--verbose", dest="verbose", action="count", default=0, help="Increase output verbosity, can be repeated")
+parser.add_argument("-r", "--rotate-source-port", dest="rotate", default=_DEFAULT_ROTATE, type=non_negative, help=f"Source port rotation period (default: {_DEFAULT_ROTATE})")
 
 controller: Controller
 
@@ -44,6 +118,8 @@ def main(args: Sequence[str] = argv[1:]) -> None:
     global controller
     just_fix_windows_console()
     arguments = vars(parser.parse_args(args))
+    adjust_level(arguments.pop("quiet"), arguments.pop("verbose"))
+    enter_namespace(arguments.pop("netns"))
 
     controller = Controller(**arguments)
     signal(SIGTERM, finish)
@@ -52,6 +128,12 @@ def main(args: Sequence[str] = argv[1:]) -> None:
     controller.start()
 
 
+def enter_namespace(netns: Optional[str]) -> None:
+    if netns is not None:
+        setns(netns, flags=0)
+        logger.info(f"Entered network namespace {netns}")
+
+
 def finish(_, __) -> None:  # type: ignore[no-untyped-def]
     global controller
     if current_process().name == "MainProcess":
@@ -1,6 +1,7 @@
 from enum import IntEnum
-from logging import getLogger
+from logging import Logger, StreamHandler, getLogger
 from os import environ
+from typing import Dict, Optional, Tuple
 
 
 class LogLevel(IntEnum):
@@ -10,7 +11,59 @@ class LogLevel(IntEnum):
     ERROR = 40
 
 
-_level = environ.get("LOG_LEVEL", "DEBUG")
+def _parse_level_name(level: str, directive: str) -> LogLevel:
+    try:
+        return LogLevel[level.strip().upper()]
+    except KeyError:
+        raise ValueError(f"Unknown log level '{level}' in LOG_LEVEL directive '{directive}'!")
+
+
+def _parse_log_level(value: str) -> Tuple[LogLevel, Dict[str, LogLevel]]:
+    default = LogLevel.DEBUG
+    modules: Dict[str, LogLevel] = dict()
+    for directive in value.split(","):
+        if len(directive.strip()) == 0:
+            continue
+        module, _, level = directive.strip().rpartition("=")
+        if len(module) > 0:
+            modules[module.strip()] = _parse_level_name(level, directive)
+        else:
+            default = _parse_level_name(level, directive)
+    return default, modules
+
+
+_levels: Optional[Tuple[LogLevel, Dict[str, LogLevel]]] = None
 
 logger = getLogger(__name__)
-logger.setLevel(LogLevel[_level])
+logger.addHandler(StreamHandler())
+
+
+def _load_log_level() -> Tuple[LogLevel, Dict[str, LogLevel]]:
+    global _levels
+    if _levels is None:
+        try:
+            _levels = _parse_log_level(environ.get("LOG_LEVEL", "DEBUG"))
+        except ValueError as e:
+            raise SystemExit(f"Invalid LOG_LEVEL environmental variable: {e}")
+        logger.setLevel(_levels[0])
+    return _levels
+
+
+def get_logger(module: str) -> Logger:
+    _, modules = _load_log_level()
+    child = logger.getChild(module)
+    if module in modules:
+        child.setLevel(modules[module])
+    return child
+
+
+def adjust_level(quiet: bool, verbose: int) -> None:
+    default, modules = _load_log_level()
+    if quiet:
+        logger.setLevel(LogLevel.ERROR)
+        for module in modules:
+            logger.getChild(module).setLevel(LogLevel.ERROR)
+    elif verbose > 0:
+        logger.setLevel(max(default - verbose * 10, LogLevel.DEBUG))
+        for module, level in modules.items():
+            logger.getChild(module).setLevel(max(level - verbose * 10, LogLevel.DEBUG))
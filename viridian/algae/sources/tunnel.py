@@ -1,15 +1,20 @@
 from fcntl import ioctl
-from ipaddress import IPv4Address
-from os import O_RDWR, getegid, geteuid, open, read, write
-from socket import AF_INET, SOCK_DGRAM, socket
+from ipaddress import IPv4Address, IPv4Network
+from os import O_CREAT, O_EXCL, O_NOFOLLOW, O_RDONLY, O_RDWR, O_WRONLY, close, fstat, getegid, geteuid, open, read, write
+from pathlib import Path
+from socket import AF_INET, IP_TOS, IPPROTO_IP, SOCK_DGRAM, SOL_SOCKET, socket
+from stat import S_IMODE, S_ISDIR, S_ISREG
 from struct import pack
-from typing import Tuple
+from time import monotonic
+from typing import Optional, Tuple
 
 from colorama import Fore
-from pyroute2 import IPRoute
+from pyroute2 import IPRoute, NetlinkError
 
 from .crypto import decrypt_symmetric, encrypt_symmetric
-from .outputs import logger
+from .outputs import get_logger
+
+logger = get_logger("tunnel")
 
 _UNIX_TUNSETIFF = 0x400454CA
 _UNIX_TUNSETOWNER = 0x400454CC
@@ -19,7 +24,24 @@ _UNIX_IFF_TUN = 0x0001
 _UNIX_IFF_NO_PI = 0x1000
 
 _UNIX_TUN_DEVICE = "/dev/net/tun"
+_ROUTE_STATE_DIR = "/run/seaside"
+_ROUTE_STATE_FILE = "{}.route"
+_ROUTE_STATE_MAX_LEN = 64
+_RP_FILTER_PATH = "/proc/sys/net/ipv4/conf/{}/rp_filter"
+_RP_FILTER_STRICT = 1
+_FALLBACK_ROUTE_METRIC = 1024
+_MAX_EMPTY_READS = 8
 _UNIX_IFNAMSIZ = 16
+_UNIX_SO_MARK = 36
+
+
+def _route_state_path(name: str) -> Path:
+    state_dir = Path(_ROUTE_STATE_DIR)
+    state_dir.mkdir(mode=0o700, parents=True, exist_ok=True)
+    dir_state = state_dir.lstat()
+    if not S_ISDIR(dir_state.st_mode) or dir_state.st_uid != geteuid() or S_IMODE(dir_state.st_mode) & 0o022:
+        raise RuntimeError(f"Route state directory ({state_dir}) must be a directory owned by current user and writable only by it!")
+    return state_dir / _ROUTE_STATE_FILE.format(name)
 
 
 def _create_tunnel(name: str) -> int:
@@ -33,19 +55,53 @@ def _create_tunnel(name: str) -> int:
     return descriptor
 
 
+def _reverse_path_warning(interface: str) -> Optional[str]:
+    rp_filter = max(int(Path(_RP_FILTER_PATH.format(name)).read_text()) for name in ("all", interface))
+    if rp_filter == _RP_FILTER_STRICT:
+        return f"Strict reverse path filtering is enabled on {interface}, packets from caerulean will be dropped, relax it with 'sysctl -w net.ipv4.conf.{interface}.rp_filter=2'!"
+    return None
+
+
 class Tunnel:
-    def __init__(self, name: str, encode: bool, mtu: int, buff: int, addr: IPv4Address, sea_port: int):
+    def __init__(
+        self,
+        name: str,
+        encode: bool,
+        mtu: int,
+        buff: int,
+        addr: IPv4Address,
+        sea_port: int,
+        rotate: int,
+        gateway: Optional[IPv4Address],
+        dscp: int,
+        mark: int,
+        fallback: bool,
+        max_drops: int,
+        routing: bool,
+        local: Optional[IPv4Address],
+    ):
         self._mtu = mtu
         self._name = name
         self._encode = encode
         self._buffer = buff
         self._address = str(addr)
         self._sea_port = sea_port
+        self._rotate = rotate
+        self._gateway = gateway
+        self._dscp = dscp
+        self._mark = mark
+        self._fallback = fallback
+        self._max_drops = max_drops
+        self._routing = routing
+        self._local = local
 
         self._def_route, self._def_intf = "", ""
         self._def_ip = "127.0.0.1"
         self._operational = False
 
+        self._state_file = _route_state_path(name)
+        self._recover_default_route()
+
         self._descriptor = _create_tunnel(name)
         logger.info(f"Tunnel {Fore.BLUE}{self._name}{Fore.RESET} created (buffer: {Fore.BLUE}{buff}{Fore.RESET})")
 
@@ -53,6 +109,10 @@ class Tunnel:
     def operational(self) -> bool:
         return self._operational
 
+    @property
+    def name(self) -> str:
+        return self._name
+
     @property
     def default_ip(self) -> str:
         return self._def_ip
@@ -73,15 +133,76 @@ class Tunnel:
 
     def _get_default_network(self) -> Tuple[int, str]:
         with IPRoute() as ip:
+            if self._local is not None:
+                local_iface_opts = ip.get_addr(address=str(self._local))
+                if len(local_iface_opts) == 0:
+                    raise ValueError(f"Local address ({self._local}) is not assigned to any interface!")
+                return local_iface_opts[0]["prefixlen"], str(self._local)
             caerulean_dev = dict(ip.route("get", dst=self._address)[0]["attrs"])["RTA_OIF"]
             caerulean_iface_opts = ip.get_addr(index=caerulean_dev)[0]
             return caerulean_iface_opts["prefixlen"], dict(caerulean_iface_opts["attrs"])["IFA_ADDRESS"]
 
+    def _save_route_state(self) -> None:
+        descriptor = open(self._state_file, O_WRONLY | O_CREAT | O_EXCL | O_NOFOLLOW, 0o600)
+        try:
+            write(descriptor, f"{self._def_route} {self._def_intf}".encode())
+        finally:
+            close(descriptor)
+
+    def _load_route_state(self) -> Optional[Tuple[str, str]]:
+        try:
+            descriptor = open(self._state_file, O_RDONLY | O_NOFOLLOW)
+        except FileNotFoundError:
+            return None
+        try:
+            state = fstat(descriptor)
+            if not S_ISREG(state.st_mode) or state.st_uid != geteuid() or S_IMODE(state.st_mode) & 0o077:
+                raise ValueError("file is not a private regular file")
+            gateway, interface = read(descriptor, _ROUTE_STATE_MAX_LEN).decode().split()
+            return str(IPv4Address(gateway)), interface
+        finally:
+            close(descriptor)
+
+    def _discard_route_state(self) -> None:
+        try:
+            self._state_file.unlink()
+        except FileNotFoundError:
+            pass
+
+    def _recover_default_route(self) -> None:
+        try:
+            state = self._load_route_state()
+        except (OSError, ValueError) as e:
+            logger.warning(f"Default route state in {Fore.YELLOW}{self._state_file}{Fore.RESET} is invalid and will be discarded: {e}")
+            self._discard_route_state()
+            return
+        if state is None:
+            return
+        gateway, interface = state
+        logger.warning(f"Default route state left by previous run found in {Fore.YELLOW}{self._state_file}{Fore.RESET}, restoring it")
+        with IPRoute() as ip:
+            interface_opts = ip.link_lookup(ifname=interface)
+            if len(interface_opts) == 0:
+                logger.warning(f"Interface {Fore.YELLOW}{interface}{Fore.RESET} from default route state doesn't exist, state will be discarded")
+            else:
+                ip.route("replace", dst="default", gateway=gateway, oif=interface_opts[0])
+                try:
+                    ip.route("del", dst="default", gateway=gateway, oif=interface_opts[0], priority=_FALLBACK_ROUTE_METRIC)
+                except NetlinkError:
+                    pass
+                logger.info(f"Default route restored (via {Fore.YELLOW}{gateway}{Fore.RESET} dev {Fore.YELLOW}{interface}{Fore.RESET})")
+        self._discard_route_state()
+
     def up(self) -> None:
         self._def_route, self._def_intf = self._get_default_route()
         def_cidr, self._def_ip = self._get_default_network()
         logger.info(f"Default route saved (via {Fore.YELLOW}{self._def_route}{Fore.RESET} dev {Fore.YELLOW}{self._def_intf}{Fore.RESET})")
 
+        tunnel_network = IPv4Network(f"{self._def_ip}/{def_cidr}", strict=False)
+        if self._gateway is not None and self._gateway not in tunnel_network:
+            raise ValueError(f"Tunnel gateway ({self._gateway}) is not in tunnel network ({tunnel_network})!")
+        tunnel_gateway = self._def_ip if self._gateway is None else str(self._gateway)
+
         with IPRoute() as ip:
             tunnel_dev = ip.link_lookup(ifname=self._name)[0]
             ip.link("set", index=tunnel_dev, mtu=self._mtu)
@@ -90,34 +211,99 @@ class Tunnel:
             logger.info(f"Tunnel IP address set to {Fore.BLUE}{self._def_ip}{Fore.RESET}")
             ip.link("set", index=tunnel_dev, state="up")
             logger.info(f"Tunnel {Fore.GREEN}enabled{Fore.RESET}")
-            ip.route("replace", dst="default", gateway=self._def_ip, oif=tunnel_dev)
-            logger.info(f"Tunnel set as default route (via {Fore.YELLOW}{self._def_ip}{Fore.RESET} dev {Fore.YELLOW}{self._name}{Fore.RESET})")
+            if self._local is not None:
+                self._check_reverse_path(ip)
+            if self._routing:
+                self._set_default_route(ip, tunnel_dev, tunnel_gateway)
+            else:
+                logger.warning(f"Routing is not configured, traffic should be routed to {Fore.YELLOW}{self._name}{Fore.RESET} manually!")
         self._operational = True
 
+    def _set_default_route(self, ip: IPRoute, tunnel_dev: int, tunnel_gateway: str) -> None:
+        self._save_route_state()
+        ip.route("replace", dst="default", gateway=tunnel_gateway, oif=tunnel_dev)
+        logger.info(f"Tunnel set as default route (via {Fore.YELLOW}{tunnel_gateway}{Fore.RESET} dev {Fore.YELLOW}{self._name}{Fore.RESET})")
+        if self._fallback:
+            default_dev = ip.link_lookup(ifname=self._def_intf)[0]
+            ip.route("add", dst="default", gateway=self._def_route, oif=default_dev, priority=_FALLBACK_ROUTE_METRIC)
+            logger.warning(f"Fallback direct route added (via {Fore.YELLOW}{self._def_route}{Fore.RESET} dev {Fore.YELLOW}{self._def_intf}{Fore.RESET}), traffic may leak!")
+
+    def _check_reverse_path(self, ip: IPRoute) -> None:
+        local_iface_opts = ip.get_addr(address=str(self._local))[0]
+        caerulean_dev = dict(ip.route("get", dst=self._address)[0]["attrs"])["RTA_OIF"]
+        if caerulean_dev != local_iface_opts["index"]:
+            warning = _reverse_path_warning(ip.get_links(local_iface_opts["index"])[0].get_attr("IFLA_IFNAME"))
+            if warning is not None:
+                logger.warning(warning)
+
+    def _restore_default_route(self, ip: IPRoute) -> None:
+        default_dev = ip.link_lookup(ifname=self._def_intf)[0]
+        ip.route("replace", dst="default", gateway=self._def_route, oif=default_dev)
+        logger.info(f"Default route restored (via {Fore.YELLOW}{self._def_route}{Fore.RESET} dev {Fore.YELLOW}{self._def_intf}{Fore.RESET})")
+        if self._fallback:
+            try:
+                ip.route("del", dst="default", gateway=self._def_route, oif=default_dev, priority=_FALLBACK_ROUTE_METRIC)
+                logger.info("Fallback direct route removed")
+            except NetlinkError as e:
+                logger.warning(f"Fallback direct route couldn't be removed: {e}")
+        self._discard_route_state()
+
     def down(self) -> None:
         with IPRoute() as ip:
             tunnel_dev = ip.link_lookup(ifname=self._name)[0]
-            default_dev = ip.link_lookup(ifname=self._def_intf)[0]
-            ip.route("replace", dst="default", gateway=self._def_route, oif=default_dev)
-            logger.info(f"Default route restored (via {Fore.YELLOW}{self._def_route}{Fore.RESET} dev {Fore.YELLOW}{self._def_intf}{Fore.RESET})")
+            if self._routing:
+                self._restore_default_route(ip)
             ip.link("set", index=tunnel_dev, state="down")
             logger.info(f"Tunnel {Fore.GREEN}disabled{Fore.RESET}")
         self._operational = False
 
+    def _open_sending_socket(self) -> socket:
+        gate = socket(AF_INET, SOCK_DGRAM)
+        gate.setsockopt(IPPROTO_IP, IP_TOS, self._dscp << 2)
+        if self._mark != 0:
+            gate.setsockopt(SOL_SOCKET, _UNIX_SO_MARK, pack("I", self._mark))
+        gate.bind((self._def_ip, 0))
+        return gate
+
     def send_to_caerulean(self) -> None:
-        with socket(AF_INET, SOCK_DGRAM) as gate:
-            gate.bind((self._def_ip, 0))
+        gate = self._open_sending_socket()
+        rotated, empty = monotonic(), 0
+        try:
             while self._operational:
                 packet = read(self._descriptor, self._buffer)
+                if len(packet) == 0:
+                    empty += 1
+                    if empty >= _MAX_EMPTY_READS:
+                        raise RuntimeError(f"Tunnel {self._name} returned no data {empty} times in a row, it might be down!")
+                    continue
+                empty = 0
+                if len(packet) > self._mtu:
+                    logger.warning(f"Dropping {len(packet)} bytes packet exceeding tunnel MTU ({self._mtu})")
+                    continue
+                if self._rotate > 0 and monotonic() - rotated >= self._rotate:
+                    gate.close()
+                    gate, rotated = self._open_sending_socket(), monotonic()
+                    logger.debug(f"Sending source port rotated to {gate.getsockname()[1]}")
                 logger.debug(f"Sending {len(packet)} bytes to caerulean {self._address}:{self._sea_port}")
                 packet = packet if not self._encode else encrypt_symmetric(packet)
                 gate.sendto(packet, (self._address, self._sea_port))
+        finally:
+            gate.close()
 
     def receive_from_caerulean(self) -> None:
         with socket(AF_INET, SOCK_DGRAM) as gate:
             gate.bind((self._def_ip, self._sea_port))
+            drops = 0
             while self._operational:
                 packet = gate.recv(self._buffer)
-                packet = packet if not self._encode else decrypt_symmetric(packet)
+                try:
+                    packet = packet if not self._encode else decrypt_symmetric(packet)
+                    drops = 0
+                except ValueError as e:
+                    drops += 1
+                    logger.warning(f"Dropping {len(packet)} bytes from caerulean ({drops} in a row): {e}")
+                    if drops >= self._max_drops:
+                        raise RuntimeError(f"Couldn't decrypt {drops} packets from caerulean in a row!")
+                    continue
                 logger.debug(f"Receiving {len(packet)} bytes from caerulean {self._address}:{self._sea_port}")
                 write(self._descriptor, packet)
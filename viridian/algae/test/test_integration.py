@@ -1,5 +1,7 @@
 from logging import getLogger
-from os import environ
+from multiprocessing import Pipe, Process
+from multiprocessing.connection import Connection
+from os import close, environ
 from random import choice, randint
 from re import compile
 from socket import AF_INET, SHUT_WR, SOCK_DGRAM, SOCK_STREAM, gethostbyname, socket
@@ -10,8 +12,14 @@ from typing import Generator, List
 from urllib.request import urlopen, urlretrieve
 
 import pytest
+from pyroute2 import IPRoute
+from pyroute2.netns import create, remove
+
+from sources.main import enter_namespace
+from sources.tunnel import _create_tunnel
 
 _PING_VERIFIER = compile(r"(\d+) packets transmitted, (\d+) packets received, (\d+)% packet loss")
+_NAMESPACE_TUNNEL = "seatest"
 
 logger = getLogger(__name__)
 
@@ -89,3 +97,30 @@ def test_http_protocol() -> None:
     assert response.status == 200
     contents = response.fp.read()
     assert "<h1>Example Domain</h1>" in contents.decode()
+
+
+def _create_tunnel_in_namespace(namespace: str, pipe: Connection) -> None:
+    enter_namespace(namespace)
+    descriptor = _create_tunnel(_NAMESPACE_TUNNEL)
+    with IPRoute() as ip:
+        pipe.send(len(ip.link_lookup(ifname=_NAMESPACE_TUNNEL)) > 0)
+    pipe.poll(5)
+    close(descriptor)
+
+
+def test_enter_namespace() -> None:
+    namespace = f"seatest{randint(0, 65535)}"
+    logger.info(f"Testing tunnel creation in network namespace {namespace}")
+    create(namespace)
+    try:
+        parent, child = Pipe()
+        process = Process(target=_create_tunnel_in_namespace, args=(namespace, child))
+        process.start()
+        assert parent.poll(5) and parent.recv()
+        with IPRoute() as ip:
+            assert len(ip.link_lookup(ifname=_NAMESPACE_TUNNEL)) == 0
+        parent.send(True)
+        process.join()
+        assert process.exitcode == 0
+    finally:
+        remove(namespace)
@@ -0,0 +1,559 @@
+from ipaddress import IPv4Address
+from pathlib import Path
+from random import randint
+from socket import AF_INET, AF_UNIX, IP_TOS, IPPROTO_IP, SOCK_DGRAM, SOCK_STREAM, SOL_SOCKET, socket, socketpair
+from struct import unpack
+from subprocess import CompletedProcess
+from threading import Thread
+from typing import Any, Callable, Dict, Generator, List, Optional, Tuple
+
+import pytest
+from pyroute2 import NetlinkError
+
+from sources.control import ConnectionClosed, Controller, ControlTimeout, TunnelNotCarryingTraffic, _receive_message
+from sources.crypto import MalformedMessage, Status, TruncatedMessage, decode_message, encode_message
+from sources.outputs import LogLevel, _load_log_level, _parse_log_level, get_logger
+from sources.tunnel import _FALLBACK_ROUTE_METRIC, _MAX_EMPTY_READS, _UNIX_SO_MARK, Tunnel, _reverse_path_warning
+
+
+_TunnelFactory = Callable[..., Tunnel]
+_ControllerFactory = Callable[..., Controller]
+
+
+def _address_message(index: int, address: str, prefix: int, label: str) -> Dict[str, Any]:
+    return dict(index=index, prefixlen=prefix, attrs=[("IFA_ADDRESS", address), ("IFA_LABEL", label)])
+
+
+class _FakeLink:
+    def __init__(self, name: str) -> None:
+        self._name = name
+
+    def get_attr(self, attribute: str) -> str:
+        return dict(IFLA_IFNAME=self._name)[attribute]
+
+
+class _FakeIPRoute:
+    def __init__(self) -> None:
+        self.links = dict(eth0=1, seatest=2, eth1=3)
+        self.addresses = [_address_message(1, "127.0.0.1", 8, "eth0"), _address_message(3, "10.1.0.2", 24, "eth1")]
+        self.routes: Dict[Tuple[str, int], Tuple[str, int]] = {("default", 0): ("127.0.0.254", 1)}
+
+    def __enter__(self) -> "_FakeIPRoute":
+        return self
+
+    def __exit__(self, *_: Any) -> None:
+        pass
+
+    def link_lookup(self, ifname: str) -> List[int]:
+        return [self.links[ifname]] if ifname in self.links else list()
+
+    def get_links(self, index: int) -> List[_FakeLink]:
+        return [_FakeLink(name) for name, link in self.links.items() if link == index]
+
+    def link(self, command: str, index: int, **_: Any) -> None:
+        pass
+
+    def addr(self, command: str, index: int, address: str, mask: int) -> None:
+        pass
+
+    def get_addr(self, index: Optional[int] = None, address: Optional[str] = None) -> List[Dict[str, Any]]:
+        return [opts for opts in self.addresses if index in (None, opts["index"]) and address in (None, dict(opts["attrs"])["IFA_ADDRESS"])]
+
+    def get_default_routes(self) -> List[Dict[str, Any]]:
+        routes = sorted((priority, route) for (dst, priority), route in self.routes.items() if dst == "default")
+        return [dict(attrs=[("RTA_GATEWAY", gateway), ("RTA_OIF", oif)]) for _, (gateway, oif) in routes]
+
+    def route(self, command: str, dst: str, gateway: Optional[str] = None, oif: int = 0, priority: int = 0) -> List[Dict[str, Any]]:
+        if command == "get":
+            return [dict(attrs=[("RTA_OIF", dict(self.get_default_routes()[0]["attrs"])["RTA_OIF"])])]
+        elif command == "replace" and gateway is not None:
+            self.routes[(dst, priority)] = (gateway, oif)
+        elif command == "add" and gateway is not None and (dst, priority) not in self.routes:
+            self.routes[(dst, priority)] = (gateway, oif)
+        elif command == "del" and self.routes.get((dst, priority)) == (gateway, oif):
+            del self.routes[(dst, priority)]
+        else:
+            raise NetlinkError(17 if command == "add" else 3)
+        return list()
+
+
+class _FakeProcess:
+    def __init__(self, target: Callable[[], None], name: str, daemon: bool) -> None:
+        self.name = name
+        self.sentinel = -1
+        self.exitcode: Optional[int] = None
+
+    def start(self) -> None:
+        pass
+
+    def terminate(self) -> None:
+        self.exitcode = -15
+
+    def is_alive(self) -> bool:
+        return self.exitcode is None
+
+
+@pytest.fixture(scope="function")
+def ip_route(tmp_path: Path, monkeypatch: pytest.MonkeyPatch) -> Generator[_FakeIPRoute, None, None]:
+    table = _FakeIPRoute()
+    monkeypatch.setattr("sources.tunnel.IPRoute", lambda: table)
+    monkeypatch.setattr("sources.tunnel._create_tunnel", lambda name: -1)
+    monkeypatch.setattr("sources.tunnel._ROUTE_STATE_DIR", str(tmp_path / "seaside"))
+    monkeypatch.setattr("sources.control.Process", _FakeProcess)
+    yield table
+
+
+@pytest.fixture(scope="function")
+def make_tunnel(ip_route: _FakeIPRoute) -> Generator[_TunnelFactory, None, None]:
+    def make(**arguments: Any) -> Tunnel:
+        defaults: Dict[str, Any] = dict(name="seatest", encode=False, mtu=1500, buff=2000, addr=IPv4Address("127.0.0.1"), sea_port=8542, rotate=0, gateway=None, dscp=0, mark=0)
+        defaults.update(fallback=False, max_drops=16, routing=True, local=None)
+        return Tunnel(**{**defaults, **arguments})
+
+    yield make
+
+
+@pytest.fixture(scope="function")
+def make_controller(ip_route: _FakeIPRoute) -> Generator[_ControllerFactory, None, None]:
+    def make(**arguments: Any) -> Controller:
+        defaults: Dict[str, Any] = dict(name="seatest", encode=False, mtu=1500, buff=2000, addr=IPv4Address("127.0.0.1"), sea_port=8542, ctrl_port=8543, ctrl_timeout=5.0)
+        defaults.update(local=None, rotate=0, probe=0, backoff=1.0, probe_resolver=IPv4Address("127.0.0.53"), probe_host="example.com", gateway=None, dscp=0, mark=0)
+        defaults.update(fallback=False, max_drops=16, routing=True, suppress=False)
+        defaults.update(up_hook=None, down_hook=None, up_hook_required=False)
+        return Controller(**{**defaults, **arguments})
+
+    yield make
+
+
+def test_message_round_trip() -> None:
+    data = bytes(randint(0, 255) for _ in range(64))
+    status, decoded = decode_message(encode_message(Status.PUBLIC, data))
+    assert status == Status.PUBLIC
+    assert decoded == data
+
+
+def test_message_truncated() -> None:
+    data = b"seaside"
+    message = encode_message(Status.SUCCESS, data)
+    data_end = message[3] + 3 + len(data)
+    for length in range(0, data_end):
+        with pytest.raises(TruncatedMessage):
+            decode_message(message[:length])
+    assert decode_message(message[:data_end]) == (Status.SUCCESS, data)
+
+
+def test_message_crafted_length() -> None:
+    message = bytearray(encode_message(Status.SUCCESS, b"seaside"))
+    offset = message[3]
+    message[offset + 1 : offset + 3] = (65535).to_bytes(2, "big")
+    with pytest.raises(TruncatedMessage):
+        decode_message(bytes(message))
+
+
+def test_message_crafted_pointer() -> None:
+    message = bytearray(encode_message(Status.SUCCESS))
+    for pointer in range(0, 4):
+        message[3] = pointer
+        with pytest.raises(MalformedMessage):
+            decode_message(bytes(message))
+
+
+def test_send_to_caerulean_rotation(make_tunnel: _TunnelFactory, monkeypatch: pytest.MonkeyPatch) -> None:
+    clock = [0.0]
+    packets = [b"first", b"second", b"third"]
+    with socket(AF_INET, SOCK_DGRAM) as caerulean:
+        caerulean.bind(("127.0.0.1", 0))
+        caerulean.settimeout(1.0)
+        tunnel = make_tunnel(sea_port=caerulean.getsockname()[1], rotate=10)
+
+        def read(descriptor: int, length: int) -> bytes:
+            if len(packets) == 0:
+                tunnel.down()
+                return bytes()
+            clock[0] += 6
+            return packets.pop(0)
+
+        monkeypatch.setattr("sources.tunnel.read", read)
+        monkeypatch.setattr("sources.tunnel.monotonic", lambda: clock[0])
+        tunnel.up()
+        tunnel.send_to_caerulean()
+        received = [caerulean.recvfrom(2000) for _ in range(3)]
+    assert [packet for packet, _ in received] == [b"first", b"second", b"third"]
+    first, second, third = [address[1] for _, address in received]
+    assert first != second and second == third
+
+
+def test_sending_socket_dscp(make_tunnel: _TunnelFactory) -> None:
+    tunnel = make_tunnel(dscp=46)
+    with tunnel._open_sending_socket() as gate:
+        assert gate.getsockopt(IPPROTO_IP, IP_TOS) == 46 << 2
+
+
+def test_sending_socket_mark(make_tunnel: _TunnelFactory) -> None:
+    for mark in (0x5EA, 0xFFFFFFFF):
+        tunnel = make_tunnel(mark=mark)
+        with tunnel._open_sending_socket() as gate:
+            assert unpack("I", gate.getsockopt(SOL_SOCKET, _UNIX_SO_MARK, 4)) == (mark,)
+
+
+def test_send_to_caerulean_empty_reads(make_tunnel: _TunnelFactory, monkeypatch: pytest.MonkeyPatch) -> None:
+    packets = [bytes(1501), b"packet"]
+    reads: List[int] = list()
+
+    def read(descriptor: int, length: int) -> bytes:
+        reads.append(descriptor)
+        return packets.pop(0) if len(packets) > 0 else bytes()
+
+    monkeypatch.setattr("sources.tunnel.read", read)
+    with socket(AF_INET, SOCK_DGRAM) as receiver:
+        receiver.bind(("127.0.0.1", 0))
+        receiver.settimeout(1)
+        tunnel = make_tunnel(mtu=1500, sea_port=receiver.getsockname()[1])
+        tunnel.up()
+        with pytest.raises(RuntimeError):
+            tunnel.send_to_caerulean()
+        assert len(reads) == _MAX_EMPTY_READS + 2
+        assert receiver.recv(2000) == b"packet"
+        receiver.setblocking(False)
+        with pytest.raises(BlockingIOError):
+            receiver.recv(2000)
+
+
+def test_receive_from_caerulean_drops(make_tunnel: _TunnelFactory, monkeypatch: pytest.MonkeyPatch) -> None:
+    decrypted: List[bytes] = list()
+    written: List[bytes] = list()
+
+    class _PairedSocket(socket):
+        def bind(self, address: Any) -> None:
+            pass
+
+    def decrypt_symmetric(packet: bytes) -> bytes:
+        decrypted.append(packet)
+        if packet == b"bad":
+            raise ValueError("Decryption failed")
+        return packet
+
+    def write(descriptor: int, packet: bytes) -> int:
+        written.append(packet)
+        return len(packet)
+
+    local, remote = socketpair(AF_UNIX, SOCK_DGRAM)
+    receiver = _PairedSocket(AF_UNIX, SOCK_DGRAM, fileno=local.detach())
+    monkeypatch.setattr("sources.tunnel.socket", lambda family, kind: receiver)
+    monkeypatch.setattr("sources.tunnel.decrypt_symmetric", decrypt_symmetric)
+    tunnel = make_tunnel(encode=True, max_drops=3)
+    tunnel.up()
+    monkeypatch.setattr("sources.tunnel.write", write)
+    with remote:
+        for packet in [b"bad"] * 2 + [b"good"] + [b"bad"] * 4:
+            remote.send(packet)
+        with pytest.raises(RuntimeError):
+            tunnel.receive_from_caerulean()
+    assert decrypted == [b"bad"] * 2 + [b"good"] + [b"bad"] * 3
+    assert written == [b"good"]
+
+
+def test_log_level_default_only() -> None:
+    assert _parse_log_level("info") == (LogLevel.INFO, dict())
+
+
+def test_log_level_module_directives() -> None:
+    assert _parse_log_level("info,tunnel=warning") == (LogLevel.INFO, dict(tunnel=LogLevel.WARNING))
+    assert _parse_log_level("control=error, warning,") == (LogLevel.WARNING, dict(control=LogLevel.ERROR))
+
+
+def test_log_level_bad_directive() -> None:
+    with pytest.raises(ValueError):
+        _parse_log_level("info,tunnel=loud")
+    with pytest.raises(ValueError):
+        _parse_log_level("tunnel=")
+
+
+def test_log_level_environment_error(monkeypatch: pytest.MonkeyPatch) -> None:
+    monkeypatch.setattr("sources.outputs._levels", None)
+    monkeypatch.setenv("LOG_LEVEL", "info,tunnel=loud")
+    with pytest.raises(SystemExit):
+        _load_log_level()
+
+
+def test_receive_message_closed() -> None:
+    local, remote = socketpair()
+    remote.close()
+    with local, pytest.raises(ConnectionClosed):
+        _receive_message(local)
+
+
+def test_receive_message_chunked() -> None:
+    data = b"seaside"
+    message = encode_message(Status.SUCCESS, data)
+    local, remote = socketpair()
+    with local, remote:
+        for chunk in (message[:2], message[2:8], message[8:]):
+            remote.sendall(chunk)
+        remote.close()
+        assert decode_message(_receive_message(local)) == (Status.SUCCESS, data)
+
+
+def test_receive_message_truncated() -> None:
+    data = b"seaside"
+    message = encode_message(Status.SUCCESS, data)
+    local, remote = socketpair()
+    with local, remote:
+        remote.sendall(message[: message[3] + 3 + len(data) - 1])
+        remote.close()
+        with pytest.raises(TruncatedMessage):
+            decode_message(_receive_message(local))
+
+
+def test_fallback_default_route(make_tunnel: _TunnelFactory, ip_route: _FakeIPRoute, tmp_path: Path) -> None:
+    state_file = tmp_path / "seaside" / "seatest.route"
+    tunnel = make_tunnel(fallback=True)
+
+    tunnel.up()
+    assert ip_route.routes == {("default", 0): ("127.0.0.1", 2), ("default", _FALLBACK_ROUTE_METRIC): ("127.0.0.254", 1)}
+    assert state_file.read_text() == "127.0.0.254 eth0" and state_file.stat().st_mode & 0o777 == 0o600
+    tunnel.down()
+    assert ip_route.routes == {("default", 0): ("127.0.0.254", 1)}
+    assert not state_file.exists()
+
+    tunnel.up()
+    del ip_route.routes[("default", _FALLBACK_ROUTE_METRIC)]
+    tunnel.down()
+    assert ip_route.routes == {("default", 0): ("127.0.0.254", 1)}
+
+
+def test_tunnel_gateway(make_tunnel: _TunnelFactory, ip_route: _FakeIPRoute, monkeypatch: pytest.MonkeyPatch) -> None:
+    monkeypatch.setattr(Tunnel, "_get_default_route", lambda self: ("127.0.0.254", "eth0"))
+    monkeypatch.setattr(Tunnel, "_get_default_network", lambda self: (24, "10.1.0.2"))
+
+    with pytest.raises(ValueError):
+        make_tunnel(gateway=IPv4Address("10.2.0.1")).up()
+    assert ip_route.routes == {("default", 0): ("127.0.0.254", 1)}
+
+    tunnel = make_tunnel(gateway=IPv4Address("10.1.0.1"))
+    tunnel.up()
+    assert ip_route.routes == {("default", 0): ("10.1.0.1", 2)}
+    tunnel.down()
+    assert ip_route.routes == {("default", 0): ("127.0.0.254", 1)}
+
+def test_no_routing(make_tunnel: _TunnelFactory, ip_route: _FakeIPRoute, tmp_path: Path) -> None:
+    tunnel = make_tunnel(fallback=True, routing=False)
+
+    tunnel.up()
+    assert ip_route.routes == {("default", 0): ("127.0.0.254", 1)}
+    assert not (tmp_path / "seaside" / "seatest.route").exists()
+    tunnel.down()
+    assert ip_route.routes == {("default", 0): ("127.0.0.254", 1)}
+    assert not (tmp_path / "seaside" / "seatest.route").exists()
+
+def test_recover_default_route(make_tunnel: _TunnelFactory, ip_route: _FakeIPRoute, tmp_path: Path) -> None:
+    state_file = tmp_path / "seaside" / "seatest.route"
+    state_file.parent.mkdir(mode=0o700)
+    state_file.write_text("127.0.0.254 eth0")
+    state_file.chmod(0o600)
+    ip_route.routes = {("default", 0): ("127.0.0.1", 2), ("default", _FALLBACK_ROUTE_METRIC): ("127.0.0.254", 1)}
+
+    make_tunnel()
+    assert ip_route.routes == {("default", 0): ("127.0.0.254", 1)}
+    assert not state_file.exists()
+
+
+def test_recover_default_route_discard(make_tunnel: _TunnelFactory, ip_route: _FakeIPRoute, tmp_path: Path) -> None:
+    state_file = tmp_path / "seaside" / "seatest.route"
+    state_file.parent.mkdir(mode=0o700)
+    ip_route.routes = {("default", 0): ("127.0.0.1", 2)}
+
+    for content, mode in (("127.0.0.254", 0o600), ("gateway eth0", 0o600), ("127.0.0.254 wlan0", 0o600), ("127.0.0.254 eth0", 0o644)):
+        state_file.write_text(content)
+        state_file.chmod(mode)
+        make_tunnel()
+        assert ip_route.routes == {("default", 0): ("127.0.0.1", 2)}
+        assert not state_file.exists()
+
+    target = tmp_path / "target"
+    target.write_text("127.0.0.254 eth0")
+    state_file.symlink_to(target)
+    make_tunnel()
+    assert ip_route.routes == {("default", 0): ("127.0.0.1", 2)}
+    assert not state_file.is_symlink() and target.exists()
+
+
+def test_exchange_control_timeout(make_controller: _ControllerFactory) -> None:
+    with socket(AF_INET, SOCK_STREAM) as listener:
+        listener.bind(("127.0.0.1", 0))
+        listener.listen(1)
+        controller = make_controller(ctrl_port=listener.getsockname()[1], ctrl_timeout=0.5)
+        with pytest.raises(ControlTimeout):
+            controller._exchange_control(Status.SUCCESS)
+
+
+def test_exchange_control_local_address(make_controller: _ControllerFactory) -> None:
+    peers: List[str] = list()
+    with socket(AF_INET, SOCK_STREAM) as listener:
+        listener.bind(("127.0.0.1", 0))
+        listener.listen(1)
+
+        def respond() -> None:
+            connection, peer = listener.accept()
+            with connection:
+                peers.append(peer[0])
+                _receive_message(connection)
+                connection.sendall(encode_message(Status.SUCCESS))
+
+        responder = Thread(target=respond)
+        responder.start()
+        controller = make_controller(ctrl_port=listener.getsockname()[1], local=IPv4Address("127.0.0.2"))
+        assert controller._exchange_control(Status.SUCCESS) == (Status.SUCCESS, None)
+        responder.join()
+    assert peers == ["127.0.0.2"]
+
+
+def test_reverse_path_warning(tmp_path: Path, monkeypatch: pytest.MonkeyPatch) -> None:
+    monkeypatch.setattr("sources.tunnel._RP_FILTER_PATH", str(tmp_path / "{}"))
+    (tmp_path / "all").write_text("0\n")
+    (tmp_path / "eth1").write_text("1\n")
+    warning = _reverse_path_warning("eth1")
+    assert warning is not None and "net.ipv4.conf.eth1.rp_filter=2" in warning
+    (tmp_path / "eth1").write_text("2\n")
+    assert _reverse_path_warning("eth1") is None
+    (tmp_path / "all").write_text("1\n")
+    (tmp_path / "eth1").write_text("0\n")
+    assert _reverse_path_warning("eth1") is not None
+
+
+def test_check_reverse_path(make_tunnel: _TunnelFactory, ip_route: _FakeIPRoute, tmp_path: Path, monkeypatch: pytest.MonkeyPatch) -> None:
+    warnings: List[str] = list()
+    monkeypatch.setattr("sources.tunnel._RP_FILTER_PATH", str(tmp_path / "{}"))
+    monkeypatch.setattr(get_logger("tunnel"), "warning", warnings.append)
+    (tmp_path / "all").write_text("0\n")
+    (tmp_path / "eth1").write_text("1\n")
+
+    for routing in (True, False):
+        tunnel = make_tunnel(local=IPv4Address("10.1.0.2"), routing=routing)
+        tunnel.up()
+        tunnel.down()
+        assert any("net.ipv4.conf.eth1.rp_filter=2" in warning for warning in warnings)
+        warnings.clear()
+
+    ip_route.routes = {("default", 0): ("10.1.0.254", 3)}
+    tunnel = make_tunnel(local=IPv4Address("10.1.0.2"))
+    tunnel.up()
+    tunnel.down()
+    assert not any("rp_filter" in warning for warning in warnings)
+
+
+def test_hook_environment(make_controller: _ControllerFactory, monkeypatch: pytest.MonkeyPatch) -> None:
+    commands: List[Tuple[str, Dict[str, str]]] = list()
+
+    def run(command: str, shell: bool, env: Dict[str, str]) -> "CompletedProcess[bytes]":
+        commands.append((command, env))
+        return CompletedProcess(command, 0)
+
+    monkeypatch.setattr("sources.control.run", run)
+    controller = make_controller(addr=IPv4Address("127.0.0.87"))
+    controller._turn_tunnel_on()
+    assert controller._run_hook("true") == 0
+    command, env = commands[0]
+    assert command == "true"
+    assert (env["SEASIDE_TUNNEL"], env["SEASIDE_ADDRESS"], env["SEASIDE_CAERULEAN"]) == ("seatest", "127.0.0.1", "127.0.0.87")
+
+
+def test_down_hook_before_termin(make_controller: _ControllerFactory, monkeypatch: pytest.MonkeyPatch) -> None:
+    events: List[str] = list()
+
+    def run(command: str, shell: bool, env: Dict[str, str]) -> "CompletedProcess[bytes]":
+        events.append(command)
+        return CompletedProcess(command, 0)
+
+    def exchange_control(status: Status) -> Tuple[Status, None]:
+        events.append(status.name)
+        return Status.SUCCESS, None
+
+    monkeypatch.setattr("sources.control.run", run)
+    controller = make_controller(down_hook="down")
+    controller._turn_tunnel_on()
+    monkeypatch.setattr(controller, "_exchange_control", exchange_control)
+    controller.break_control()
+    assert events == ["down", "TERMIN"]
+    assert not controller._interface.operational
+
+    controller._turn_tunnel_on()
+    controller._clean_tunnel()
+    controller.break_control()
+    assert events == ["down", "TERMIN", "down", "TERMIN"]
+
+
+def test_suppress_termin(make_controller: _ControllerFactory, monkeypatch: pytest.MonkeyPatch) -> None:
+    statuses: List[Status] = list()
+
+    def exchange_control(status: Status) -> Tuple[Status, None]:
+        statuses.append(status)
+        return Status.SUCCESS, None
+
+    controller = make_controller(suppress=True)
+    controller._turn_tunnel_on()
+    monkeypatch.setattr(controller, "_exchange_control", exchange_control)
+    controller.break_control()
+    assert statuses == list()
+    assert not controller._interface.operational
+    assert controller._receiver_process.exitcode is not None and controller._sender_process.exitcode is not None
+
+def test_required_up_hook_failure(make_controller: _ControllerFactory, monkeypatch: pytest.MonkeyPatch) -> None:
+    events: List[str] = list()
+
+    def run(command: str, shell: bool, env: Dict[str, str]) -> "CompletedProcess[bytes]":
+        events.append(command)
+        return CompletedProcess(command, 1)
+
+    def exchange_control(status: Status) -> Tuple[Status, None]:
+        events.append(status.name)
+        return Status.SUCCESS, None
+
+    monkeypatch.setattr("sources.control.run", run)
+    controller = make_controller(up_hook="up", down_hook="down", up_hook_required=True)
+    controller._turn_tunnel_on()
+    monkeypatch.setattr(controller, "_exchange_control", exchange_control)
+    with pytest.raises(RuntimeError):
+        controller._run_up_hook("up")
+    assert events == ["up", "TERMIN"]
+    assert not controller._interface.operational
+
+
+def test_probe_connection_retries(make_controller: _ControllerFactory, monkeypatch: pytest.MonkeyPatch) -> None:
+    queries: List[Tuple[str, str]] = list()
+    delays: List[float] = list()
+
+    def query_resolver(resolver: str, host: str) -> None:
+        queries.append((resolver, host))
+        if len(queries) < 3:
+            raise OSError("Network is unreachable")
+
+    monkeypatch.setattr("sources.control._query_resolver", query_resolver)
+    monkeypatch.setattr("sources.control.sleep", delays.append)
+    controller = make_controller(probe=3, backoff=0.5)
+    controller._probe_connection()
+    assert queries == [("127.0.0.53", "example.com")] * 3
+    assert delays == [0.5, 1.0]
+
+
+def test_probe_connection_failure(make_controller: _ControllerFactory, monkeypatch: pytest.MonkeyPatch) -> None:
+    delays: List[float] = list()
+    statuses: List[Status] = list()
+
+    def query_resolver(resolver: str, host: str) -> None:
+        raise OSError("Network is unreachable")
+
+    def exchange_control(status: Status) -> Tuple[Status, None]:
+        statuses.append(status)
+        return Status.SUCCESS, None
+
+    monkeypatch.setattr("sources.control._query_resolver", query_resolver)
+    monkeypatch.setattr("sources.control.sleep", delays.append)
+    controller = make_controller(probe=3, backoff=0.5)
+    controller._turn_tunnel_on()
+    monkeypatch.setattr(controller, "_exchange_control", exchange_control)
+    with pytest.raises(TunnelNotCarryingTraffic):
+        controller._probe_connection()
+    assert delays == [0.5, 1.0]
+    assert statuses == [Status.TERMIN]
+    assert not controller._interface.operational